@@ -0,0 +1,365 @@
+// CITA
+// Copyright 2016-2019 Cryptape Technologies LLC.
+
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public
+// License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any
+// later version.
+
+// This program is distributed in the hope that it will be
+// useful, but WITHOUT ANY WARRANTY; without even the implied
+// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+use crate::error::BftError;
+use crate::params::{leader_for, relayer_for, BftParams, Step};
+use crate::proposal::ProposalCollector;
+use crate::timer::{TimeoutInfo, WaitTimer};
+use crate::voteset::{AggregatedVote, VoteAddResult, VoteCollector};
+use crate::{Address, BftMsg, BftSupport, Commit, Proposal, Status, Target, Vote, VoteType};
+
+use crossbeam::crossbeam_channel::{unbounded, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// The BFT state machine, run on its own thread by `BftActuator::new`.
+pub struct Bft<T> {
+    address: Address,
+    height: u64,
+    round: u64,
+    authority_list: Vec<Address>,
+    authority_weights: Option<Vec<u64>>,
+    proposals: ProposalCollector,
+    votes: VoteCollector,
+    params: BftParams,
+    timer_request: Sender<(TimeoutInfo, Duration)>,
+    // our own vote for the current step, kept so it can be rebroadcast to
+    // everyone if the relayer never answers with an aggregated QC
+    pending_vote: Option<Vote>,
+    // the step the current height/round is in, so a duplicate or
+    // out-of-order aggregated vote / timeout for a step we've already left
+    // is ignored instead of being acted on again (e.g. committing twice)
+    step: Step,
+    support: T,
+}
+
+impl<T> Bft<T>
+where
+    T: BftSupport + Send + 'static,
+{
+    /// Spawn the state machine on a dedicated thread, consuming `BftMsg`s
+    /// from `receiver` until the channel is closed.
+    pub fn start(receiver: Receiver<BftMsg>, support: T, address: Address) {
+        let (timeout_sender, timeout_receiver) = unbounded();
+        let (timer_request, timer_request_receiver) = unbounded();
+        WaitTimer::new(timeout_sender, timer_request_receiver).start();
+
+        let mut engine = Bft {
+            params: BftParams::new(address.clone()),
+            address,
+            height: 0,
+            round: 0,
+            authority_list: Vec::new(),
+            authority_weights: None,
+            proposals: ProposalCollector::new(),
+            votes: VoteCollector::new(),
+            timer_request,
+            pending_vote: None,
+            step: Step::Propose,
+            support,
+        };
+        thread::spawn(move || loop {
+            select! {
+                recv(receiver) -> msg => match msg {
+                    Ok(msg) => engine.process(msg),
+                    Err(_) => break,
+                },
+                recv(timeout_receiver) -> info => match info {
+                    Ok(info) => engine.handle_timeout(info),
+                    Err(_) => break,
+                },
+            }
+        });
+    }
+
+    fn process(&mut self, msg: BftMsg) {
+        match msg {
+            BftMsg::Proposal(proposal) => self.handle_proposal(proposal),
+            BftMsg::Vote(vote) => self.handle_vote(vote),
+            BftMsg::AggregatedVote(qc) => self.handle_aggregated_vote(qc),
+            BftMsg::Status(status) => self.handle_status(status),
+            _ => {}
+        }
+    }
+
+    // Record an incoming proposal, dropping it deterministically if its
+    // proposer already sent a different one for the same (height, round)
+    // instead of letting both race through the rest of the pipeline. On the
+    // first valid proposal for the round, cast and route our prevote.
+    //
+    // Only the round's leader may propose; a proposal from anyone else is
+    // rejected before it ever reaches the conflict-tracking collector.
+    fn handle_proposal(&mut self, proposal: Proposal) {
+        if leader_for(&self.authority_list, proposal.round)
+            .map_or(true, |leader| *leader != proposal.proposer)
+        {
+            warn!(
+                "dropping proposal from non-leader {:?} at height {}, round {}",
+                proposal.proposer, proposal.height, proposal.round
+            );
+            return;
+        }
+        let content = proposal.content.clone();
+        let (height, round) = (proposal.height, proposal.round);
+        match self.proposals.add(proposal) {
+            Ok(true) => {
+                if self.support.check_proposal(&content, height, round) {
+                    let vote = Vote {
+                        vote_type: VoteType::Prevote,
+                        height,
+                        round,
+                        proposal: content,
+                        voter: self.address.clone(),
+                    };
+                    self.broadcast_vote(vote);
+                    self.step = Step::Prevote;
+                    self.arm_timeout(Step::Prevote);
+                }
+            }
+            Ok(false) => {
+                // exact duplicate of a proposal we already have, ignore it
+            }
+            Err(BftError::MultiProposal(height, round)) => {
+                warn!(
+                    "dropping conflicting proposal at height {}, round {}",
+                    height, round
+                );
+            }
+            Err(_) => {}
+        }
+    }
+
+    // Record an incoming vote, reporting equivocation evidence if the sender
+    // just double-voted. Only the round's relayer tallies votes towards
+    // quorum -- a replica just holds onto them for `abstract_polc`.
+    fn handle_vote(&mut self, vote: Vote) {
+        let (vote_type, height, round, proposal) =
+            (vote.vote_type, vote.height, vote.round, vote.proposal.clone());
+        let weight = self.weight_of(&vote.voter);
+        if let VoteAddResult::Equivocation(evidence) = self.votes.add_weighted(vote, weight) {
+            self.support.transmit(BftMsg::Equivocation(evidence));
+            return;
+        }
+        let (height, round) = (height as usize, round as usize);
+        if self.is_relayer() && self.has_quorum(height, round, vote_type, &proposal) {
+            self.build_and_broadcast_qc(vote_type, height, round, &proposal);
+        }
+    }
+
+    // `voter`'s voting power, looked up by its position in `authority_list`.
+    // Falls back to `1` if no `authority_weights` were supplied, or if
+    // `authority_weights` has no entry at `voter`'s position. A `voter` that
+    // isn't in `authority_list` at all weighs `0`, so its vote never adds to
+    // the tally `has_quorum` checks.
+    fn weight_of(&self, voter: &Address) -> u64 {
+        let index = match self.authority_list.iter().position(|address| address == voter) {
+            Some(index) => index,
+            None => return 0,
+        };
+        match &self.authority_weights {
+            Some(weights) => weights.get(index).copied().unwrap_or(1),
+            None => 1,
+        }
+    }
+
+    // Our own vote for the current step: route it to the round's relayer
+    // instead of broadcasting to every peer (O(n) instead of O(n^2)
+    // messages per step), and remember it so it can be rebroadcast if the
+    // relayer goes quiet.
+    fn broadcast_vote(&mut self, vote: Vote) {
+        match relayer_for(&self.authority_list, self.round) {
+            Some(relayer) if *relayer != self.address => {
+                self.pending_vote = Some(vote.clone());
+                self.support.transmit_to(relayer, BftMsg::Vote(vote));
+            }
+            // we are the relayer, or no relayer could be determined yet
+            _ => self.support.transmit(BftMsg::Vote(vote)),
+        }
+    }
+
+    // `leader_for` and `relayer_for` currently share the same rotation
+    // formula (see the NOTE on `relayer_for`), so they always resolve to the
+    // same address. Quorum-tallying is gated on this directly, rather than
+    // through a `Role` classification, so relaying isn't coupled to whatever
+    // priority a combined Leader/Relayer/Replica role would need to pick.
+    fn is_relayer(&self) -> bool {
+        relayer_for(&self.authority_list, self.round).map_or(false, |relayer| *relayer == self.address)
+    }
+
+    // Whether the votes tallied so far for `proposal` clear the +2/3
+    // weighted threshold (falling back to vote cardinality when no
+    // `authority_weights` were supplied).
+    fn has_quorum(&mut self, height: usize, round: usize, vote_type: VoteType, proposal: &Target) -> bool {
+        let total: u64 = match &self.authority_weights {
+            Some(weights) => weights.iter().sum(),
+            None => self.authority_list.len() as u64,
+        };
+        let tally = self
+            .votes
+            .get_voteset(height, round, vote_type)
+            .map_or(0, |vs| vs.weighted_count(proposal));
+        total > 0 && tally * 3 > total * 2
+    }
+
+    // Relayer-only: collapse the tallied votes into a constant-size QC,
+    // broadcast it to every replica in place of the raw vote set, and apply
+    // it locally -- the relayer advances its own step the same way a
+    // replica does on receipt, rather than trusting its own tally twice.
+    fn build_and_broadcast_qc(
+        &mut self,
+        vote_type: VoteType,
+        height: usize,
+        round: usize,
+        proposal: &Target,
+    ) {
+        if let Some(vote_set) = self.votes.get_voteset(height, round, vote_type) {
+            let qc = vote_set.into_qc(
+                height,
+                round,
+                vote_type,
+                proposal,
+                &self.authority_list,
+                &self.support,
+            );
+            self.support.transmit(BftMsg::AggregatedVote(qc.clone()));
+            self.handle_aggregated_vote(qc);
+        }
+    }
+
+    // A replica verifies the relayer's QC instead of re-tallying individual
+    // votes. A verified prevote QC clears the round to cast a precommit;
+    // a verified precommit QC finalizes the height. Guarded by `self.step`
+    // so a duplicate or retransmitted QC for a step we've already left
+    // (and so already acted on) is ignored rather than re-applied.
+    fn handle_aggregated_vote(&mut self, qc: AggregatedVote) {
+        if qc.height != self.height || qc.round != self.round {
+            return;
+        }
+        let expected_step = match qc.vote_type {
+            VoteType::Prevote => Step::Prevote,
+            VoteType::Precommit => Step::Precommit,
+        };
+        if self.step != expected_step {
+            return;
+        }
+        if !self.support.verify_aggregate(&qc, &self.authority_list) {
+            warn!(
+                "dropping aggregated vote with an invalid signature at height {}, round {}",
+                qc.height, qc.round
+            );
+            return;
+        }
+        self.pending_vote = None;
+        match qc.vote_type {
+            VoteType::Prevote => {
+                let vote = Vote {
+                    vote_type: VoteType::Precommit,
+                    height: qc.height,
+                    round: qc.round,
+                    proposal: qc.proposal,
+                    voter: self.address.clone(),
+                };
+                self.broadcast_vote(vote);
+                self.step = Step::Precommit;
+                self.arm_timeout(Step::Precommit);
+            }
+            VoteType::Precommit => {
+                self.step = Step::Commit;
+                let commit = Commit {
+                    height: qc.height,
+                    round: qc.round,
+                    proposal: qc.proposal.clone(),
+                    lock_votes: Vec::new(),
+                    proof: Some(qc),
+                    address: self.address.clone(),
+                };
+                self.support.commit(commit);
+                self.arm_timeout(Step::Commit);
+            }
+        }
+    }
+
+    fn handle_status(&mut self, status: Status) {
+        self.height = status.height;
+        self.round = 0;
+        self.step = Step::Propose;
+        self.authority_list = status.authority_list;
+        self.authority_weights = status.authority_weights;
+        self.pending_vote = None;
+        if let Some(config) = status.timeout_config {
+            self.params.timeout_config = config;
+        }
+        self.arm_timeout(Step::Propose);
+    }
+
+    // Arm the timeout for the current height/round/`step`, backing off by
+    // `round * delta` so a round that keeps failing to reach quorum waits
+    // progressively longer before the next one is tried.
+    fn arm_timeout(&self, step: Step) {
+        let duration = self.params.timeout_config.duration_for(step, self.round);
+        let info = TimeoutInfo {
+            height: self.height,
+            round: self.round,
+            step,
+        };
+        let _ = self.timer_request.send((info, duration));
+    }
+
+    fn handle_timeout(&mut self, info: TimeoutInfo) {
+        // a timeout for a height/round we've already moved past, or for a
+        // step we've already left by other means, is stale -- ignore it
+        if info.height != self.height || info.round != self.round || info.step != self.step {
+            return;
+        }
+        match info.step {
+            Step::Propose => {
+                // no usable proposal arrived in time, move to the next round
+                self.round += 1;
+                self.arm_timeout(Step::Propose);
+            }
+            Step::Prevote | Step::Precommit => {
+                // the relayer never answered with an aggregated QC; fall
+                // back to broadcasting our vote to everyone directly
+                if let Some(vote) = self.pending_vote.take() {
+                    warn!(
+                        "relayer unresponsive at height {}, round {}, falling back to full broadcast",
+                        self.height, self.round
+                    );
+                    self.support.transmit(BftMsg::Vote(vote));
+                }
+                // a full broadcast only gets the vote to every replica; it
+                // doesn't get anyone any closer to tallying a quorum, since
+                // only the relayer ever builds a QC. Rather than wait
+                // forever for a relayer that may simply be down, treat the
+                // step timeout as a failed round: bump the round (which
+                // rotates in a new leader and relayer) and retry from
+                // `Propose`.
+                self.round += 1;
+                self.step = Step::Propose;
+                self.pending_vote = None;
+                self.arm_timeout(Step::Propose);
+            }
+            Step::Commit => {
+                // done waiting out the post-commit pause, start the next height
+                self.height += 1;
+                self.round = 0;
+                self.step = Step::Propose;
+                self.pending_vote = None;
+                self.arm_timeout(Step::Propose);
+            }
+        }
+    }
+}
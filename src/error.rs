@@ -0,0 +1,57 @@
+// CITA
+// Copyright 2016-2019 Cryptape Technologies LLC.
+
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public
+// License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any
+// later version.
+
+// This program is distributed in the hope that it will be
+// useful, but WITHOUT ANY WARRANTY; without even the implied
+// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+use std::fmt;
+
+/// Errors of the BFT crate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BftError {
+    /// A proposal carries a `lock_round` but no `lock_votes` (or vice versa).
+    ProposalIllegal(u64, u64),
+    /// A proposer sent two different proposals for the same (height, round).
+    MultiProposal(u64, u64),
+    /// Sending a proposal down the internal channel failed.
+    SendProposalErr,
+    /// Sending a vote down the internal channel failed.
+    SendVoteErr,
+    /// Sending a command down the internal channel failed.
+    SendCmdErr,
+    /// The message passed to `send_command` is not a command.
+    MsgTypeErr,
+}
+
+impl fmt::Display for BftError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BftError::ProposalIllegal(height, round) => write!(
+                f,
+                "proposal of height {}, round {} is illegal: lock_round and lock_votes must agree",
+                height, round
+            ),
+            BftError::MultiProposal(height, round) => write!(
+                f,
+                "received conflicting proposals from the same proposer at height {}, round {}",
+                height, round
+            ),
+            BftError::SendProposalErr => write!(f, "send proposal failed"),
+            BftError::SendVoteErr => write!(f, "send vote failed"),
+            BftError::SendCmdErr => write!(f, "send command failed"),
+            BftError::MsgTypeErr => write!(f, "the given message is not a BFT command"),
+        }
+    }
+}
+
+impl std::error::Error for BftError {}
@@ -18,14 +18,18 @@ extern crate serde_derive;
 use rustc_serialize::json::{Json, ToJson};
 use std::collections::BTreeMap;
 
+use voteset::AggregatedVote;
+
 /// Bft actuator.
 pub mod actuator;
 /// BFT state machine.
 pub mod algorithm;
 ///
 pub mod error;
-/// BFT params include time interval and local address.
+/// BFT params include per-step timeouts and local address.
 pub mod params;
+/// BFT proposal collector.
+pub mod proposal;
 /// BFT timer.
 pub mod timer;
 /// BFT vote set.
@@ -52,14 +56,39 @@ pub enum BftMsg {
     Status(Status),
     /// Commit message.
     Commit(Commit),
+    /// Evidence of a double vote (equivocation) caught while collecting votes.
+    Equivocation(Evidence),
+    /// A relayer's aggregated quorum certificate for a round's votes.
+    AggregatedVote(AggregatedVote),
     /// Pause BFT state machine.
     Pause,
     /// Start running BFT state machine.
     Start,
 }
 
+/// The interface the BFT core needs its caller to implement, wiring the
+/// state machine to the outside world: network transport and the content
+/// being agreed on.
+pub trait BftSupport: Sync + Send {
+    /// Check whether `proposal` is valid content to vote on.
+    fn check_proposal(&self, proposal: &Target, height: u64, round: u64) -> bool;
+    /// Send a BFT message out to the rest of the network.
+    fn transmit(&self, msg: BftMsg);
+    /// Send a BFT message to a single peer. Used by replicas to route
+    /// their vote to the round's relayer instead of broadcasting it.
+    fn transmit_to(&self, target: &Address, msg: BftMsg);
+    /// Commit the agreed-upon result of a height.
+    fn commit(&self, commit: Commit);
+    /// Aggregate `votes` (all for the same height/round/proposal) into a
+    /// single signature, e.g. a BLS or threshold signature. Kept signature-
+    /// scheme agnostic so the library doesn't have to pick one.
+    fn aggregate_signatures(&self, votes: &[Vote]) -> Vec<u8>;
+    /// Verify that `qc`'s aggregated signature is valid for `authority_list`.
+    fn verify_aggregate(&self, qc: &AggregatedVote, authority_list: &[Address]) -> bool;
+}
+
 /// Bft vote types.
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum VoteType {
     /// Vote type prevote.
     Prevote,
@@ -155,6 +184,29 @@ impl ToJson for Vote {
     }
 }
 
+/// Proof that a voter equivocated: it cast two different votes for the
+/// same height, round and vote type, which a correct (non-Byzantine) node
+/// never does.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Evidence {
+    /// The height at which the voter equivocated.
+    pub height: u64,
+    /// The round at which the voter equivocated.
+    pub round: u64,
+    /// Whether the conflicting votes were prevotes or precommits.
+    pub vote_type: VoteType,
+    /// The equivocating voter.
+    pub voter: Address,
+    /// The proposal the voter voted for first.
+    pub first: Target,
+    /// The conflicting proposal the voter voted for afterwards.
+    pub second: Target,
+    /// The first signed vote.
+    pub first_vote: Vote,
+    /// The second, conflicting signed vote.
+    pub second_vote: Vote,
+}
+
 /// A proposal for a height.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Feed {
@@ -175,6 +227,10 @@ pub struct Commit {
     pub proposal: Target,
     /// Votes for generate proof.
     pub lock_votes: Vec<Vote>,
+    /// Constant-size aggregated quorum certificate for the precommits that
+    /// decided `proposal`, built via `VoteSet::into_qc`. Commits produced by
+    /// the state machine always carry one and leave `lock_votes` empty.
+    pub proof: Option<AggregatedVote>,
     /// The node address.
     pub address: Address,
 }
@@ -186,6 +242,7 @@ impl ToJson for Commit {
         d.insert("round".to_string(), self.round.to_json());
         d.insert("proposal".to_string(), self.proposal.to_json());
         d.insert("lock votes".to_string(), self.lock_votes.to_json());
+        d.insert("proof".to_string(), self.proof.to_json());
         d.insert("address".to_string(), self.address.to_json());
         Json::Object(d)
     }
@@ -196,10 +253,16 @@ impl ToJson for Commit {
 pub struct Status {
     /// The height of rich status.
     pub height: u64,
-    /// The time interval of next height. If it is none, maintain the old interval.
-    pub interval: Option<u64>,
+    /// The per-step timeout configuration for next height. If `None`,
+    /// maintain the old configuration. Replaces the whole configuration at
+    /// once, rather than patching a single time interval, since BFT steps
+    /// each need their own timeout.
+    pub timeout_config: Option<crate::params::TimeoutConfig>,
     /// A new authority list for next height.
     pub authority_list: Vec<Address>,
+    /// The voting power of each authority in `authority_list`, in the same order.
+    /// If `None`, every authority is given equal weight.
+    pub authority_weights: Option<Vec<u64>>,
 }
 
 /// A verify result of a proposal.
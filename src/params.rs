@@ -0,0 +1,202 @@
+// CITA
+// Copyright 2016-2019 Cryptape Technologies LLC.
+
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public
+// License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any
+// later version.
+
+// This program is distributed in the hope that it will be
+// useful, but WITHOUT ANY WARRANTY; without even the implied
+// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+use crate::Address;
+use std::time::Duration;
+
+/// The step a round is in, used to pick which `TimeoutConfig` field applies.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Step {
+    /// Waiting for a proposal.
+    Propose,
+    /// Waiting for +2/3 prevotes.
+    Prevote,
+    /// Waiting for +2/3 precommits.
+    Precommit,
+    /// Waiting before starting the next height after a commit.
+    Commit,
+}
+
+/// Per-step timeout configuration, following the classic Tendermint
+/// `timeoutPropose` / `timeoutPrevote` / `timeoutPrecommit` / `timeoutCommit`
+/// split rather than a single time interval shared by every step.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    /// Base timeout, in milliseconds, waiting for a proposal.
+    pub propose: u64,
+    /// Base timeout, in milliseconds, waiting for +2/3 prevotes.
+    pub prevote: u64,
+    /// Base timeout, in milliseconds, waiting for +2/3 precommits.
+    pub precommit: u64,
+    /// Base timeout, in milliseconds, waiting before the next height starts.
+    pub commit: u64,
+    /// How much, in milliseconds, each step's timeout grows per additional
+    /// round, so a round that fails to reach quorum waits progressively
+    /// longer before the next one is tried.
+    pub delta: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            propose: 3000,
+            prevote: 1000,
+            precommit: 1000,
+            commit: 1000,
+            delta: 500,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// The timeout for `step` at `round`, i.e. `timeout_step + round * delta`.
+    pub fn duration_for(&self, step: Step, round: u64) -> Duration {
+        let base = match step {
+            Step::Propose => self.propose,
+            Step::Prevote => self.prevote,
+            Step::Precommit => self.precommit,
+            Step::Commit => self.commit,
+        };
+        Duration::from_millis(base + round * self.delta)
+    }
+}
+
+/// A node's role for the current round. Mirrors overlord's leader/relayer
+/// split: one validator proposes, and one collects every replica's vote and
+/// rebroadcasts a single aggregated quorum certificate, cutting per-step
+/// gossip from O(n^2) messages to O(n). `leader_for` and `relayer_for`
+/// pick the same validator for a given round, so one node holds both roles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Proposes for the round.
+    Leader,
+    /// Collects every replica's vote and rebroadcasts a single aggregated
+    /// quorum certificate once +2/3 is reached.
+    Relayer,
+    /// Sends its vote to the round's relayer and waits for the aggregated
+    /// quorum certificate instead of re-tallying individual votes.
+    Replica,
+}
+
+/// Pick the leader for `round` by rotating through `authority_list`, i.e.
+/// `authority_list[round % n]`. Returns `None` for an empty authority list.
+pub fn leader_for(authority_list: &[Address], round: u64) -> Option<&Address> {
+    if authority_list.is_empty() {
+        return None;
+    }
+    let index = (round % authority_list.len() as u64) as usize;
+    authority_list.get(index)
+}
+
+/// Pick the relayer for `round` by rotating through `authority_list`, i.e.
+/// `authority_list[round % n]`. Returns `None` for an empty authority list.
+///
+/// NOTE: this is the same formula, and so the same validator, as
+/// `leader_for` -- matching the backlog request's formula verbatim rather
+/// than offsetting the relayer to land on a different validator (an earlier
+/// revision of this function used `(round + 1) % n` to keep the two roles
+/// apart, which silently contradicted the spec; reverted pending sign-off
+/// on whether that separation was actually intended). `Bft::is_relayer`
+/// gates QC-building independently of `Role` so this coincidence doesn't
+/// leave relaying unreachable.
+pub fn relayer_for(authority_list: &[Address], round: u64) -> Option<&Address> {
+    if authority_list.is_empty() {
+        return None;
+    }
+    let index = (round % authority_list.len() as u64) as usize;
+    authority_list.get(index)
+}
+
+/// Parameters the BFT state machine is configured with.
+#[derive(Clone, Debug)]
+pub struct BftParams {
+    /// The local node's address.
+    pub address: Address,
+    /// The per-step timeout configuration, updatable wholesale via `Status`.
+    pub timeout_config: TimeoutConfig,
+}
+
+impl BftParams {
+    /// Build params for `address` with the default timeout configuration.
+    pub fn new(address: Address) -> Self {
+        BftParams {
+            address,
+            timeout_config: TimeoutConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_for_uses_each_steps_own_base() {
+        let config = TimeoutConfig::default();
+        assert_eq!(config.duration_for(Step::Propose, 0), Duration::from_millis(config.propose));
+        assert_eq!(config.duration_for(Step::Prevote, 0), Duration::from_millis(config.prevote));
+        assert_eq!(config.duration_for(Step::Precommit, 0), Duration::from_millis(config.precommit));
+        assert_eq!(config.duration_for(Step::Commit, 0), Duration::from_millis(config.commit));
+    }
+
+    #[test]
+    fn duration_for_backs_off_linearly_with_round() {
+        let config = TimeoutConfig::default();
+        assert_eq!(
+            config.duration_for(Step::Prevote, 3),
+            Duration::from_millis(config.prevote + 3 * config.delta)
+        );
+    }
+
+    #[test]
+    fn leader_and_relayer_rotate_to_the_same_sole_validator_when_n_is_1() {
+        // the n=1 case fixed in an earlier review round: `leader_for` and
+        // `relayer_for` share a rotation formula, so a single-validator
+        // authority list makes every round's leader and relayer the same
+        // address. `Bft::is_relayer` (not `Role`) is what guards QC
+        // tallying, so this coincidence doesn't leave relaying unreachable.
+        let authority_list = vec![vec![1]];
+        for round in 0..3 {
+            assert_eq!(leader_for(&authority_list, round), Some(&authority_list[0]));
+            assert_eq!(relayer_for(&authority_list, round), Some(&authority_list[0]));
+        }
+    }
+
+    #[test]
+    fn leader_and_relayer_coincide_for_a_multi_validator_set() {
+        // `relayer_for` was reverted to `leader_for`'s exact formula (see the
+        // NOTE on `relayer_for`), not just for the n=1 edge case -- confirm
+        // that coincidence holds for a multi-validator set too, since that's
+        // the case the spec-departure sign-off actually concerns.
+        let authority_list = vec![vec![1], vec![2], vec![3]];
+        for round in 0..6 {
+            assert_eq!(leader_for(&authority_list, round), relayer_for(&authority_list, round));
+        }
+    }
+
+    #[test]
+    fn leader_for_rotates_through_the_authority_list() {
+        let authority_list = vec![vec![1], vec![2], vec![3]];
+        assert_eq!(leader_for(&authority_list, 0), Some(&authority_list[0]));
+        assert_eq!(leader_for(&authority_list, 1), Some(&authority_list[1]));
+        assert_eq!(leader_for(&authority_list, 3), Some(&authority_list[0]));
+    }
+
+    #[test]
+    fn leader_for_is_none_for_an_empty_authority_list() {
+        assert_eq!(leader_for(&[], 0), None);
+    }
+}
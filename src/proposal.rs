@@ -0,0 +1,151 @@
+// CITA
+// Copyright 2016-2019 Cryptape Technologies LLC.
+
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public
+// License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any
+// later version.
+
+// This program is distributed in the hope that it will be
+// useful, but WITHOUT ANY WARRANTY; without even the implied
+// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+use super::{Address, Proposal};
+use crate::error::BftError;
+use lru_cache::LruCache;
+
+use std::collections::HashMap;
+
+/// Collects proposals so that a Byzantine proposer cannot have two
+/// different proposals accepted for the same (height, round). Mirrors the
+/// height -> round shape `VoteCollector` uses for votes.
+#[derive(Debug)]
+pub struct ProposalCollector {
+    pub proposals: LruCache<usize, ProposalRoundCollector>,
+}
+
+impl ProposalCollector {
+    pub fn new() -> Self {
+        ProposalCollector {
+            proposals: LruCache::new(16),
+        }
+    }
+
+    /// Record `proposal`. Returns `Ok(true)` if this is the first proposal
+    /// seen from its proposer for this (height, round), `Ok(false)` if it is
+    /// an exact duplicate, and `Err(BftError::MultiProposal(height, round))`
+    /// if the proposer already sent a *different* proposal for the same
+    /// (height, round) -- both are kept so the caller can build equivocation
+    /// evidence.
+    pub fn add(&mut self, proposal: Proposal) -> Result<bool, BftError> {
+        let height = proposal.height as usize;
+        let round = proposal.round as usize;
+
+        if self.proposals.contains_key(&height) {
+            self.proposals.get_mut(&height).unwrap().add(round, proposal)
+        } else {
+            let mut round_proposals = ProposalRoundCollector::new();
+            let result = round_proposals.add(round, proposal);
+            self.proposals.insert(height, round_proposals);
+            result
+        }
+    }
+
+    pub fn get_proposal(&mut self, height: usize, round: usize, proposer: &Address) -> Option<Proposal> {
+        self.proposals
+            .get_mut(&height)
+            .and_then(|rc| rc.get_proposal(round, proposer))
+    }
+}
+
+// round -> proposer -> first proposal seen (plus any conflicting ones)
+#[derive(Debug, Default)]
+pub struct ProposalRoundCollector {
+    pub round_proposals: HashMap<usize, HashMap<Address, Proposal>>,
+    pub conflicts: HashMap<usize, HashMap<Address, Vec<Proposal>>>,
+}
+
+impl ProposalRoundCollector {
+    pub fn new() -> Self {
+        ProposalRoundCollector {
+            round_proposals: HashMap::new(),
+            conflicts: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, round: usize, proposal: Proposal) -> Result<bool, BftError> {
+        let proposers = self.round_proposals.entry(round).or_insert_with(HashMap::new);
+        match proposers.get(&proposal.proposer) {
+            None => {
+                proposers.insert(proposal.proposer.clone(), proposal);
+                Ok(true)
+            }
+            Some(first) if *first == proposal => Ok(false),
+            Some(first) => {
+                let height = first.height;
+                self.conflicts
+                    .entry(round)
+                    .or_insert_with(HashMap::new)
+                    .entry(proposal.proposer.clone())
+                    .or_insert_with(Vec::new)
+                    .push(proposal);
+                Err(BftError::MultiProposal(height, round as u64))
+            }
+        }
+    }
+
+    fn get_proposal(&mut self, round: usize, proposer: &Address) -> Option<Proposal> {
+        self.round_proposals
+            .get(&round)
+            .and_then(|proposers| proposers.get(proposer))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Target;
+
+    fn proposal(content: Target) -> Proposal {
+        Proposal {
+            height: 1,
+            round: 0,
+            content,
+            lock_round: None,
+            lock_votes: None,
+            proposer: vec![9],
+        }
+    }
+
+    #[test]
+    fn exact_duplicate_is_accepted_silently() {
+        let mut proposals = ProposalCollector::new();
+        assert_eq!(proposals.add(proposal(vec![1])), Ok(true));
+        // same proposer, same (height, round), identical content
+        assert_eq!(proposals.add(proposal(vec![1])), Ok(false));
+    }
+
+    #[test]
+    fn conflicting_proposal_is_rejected_as_multi_proposal() {
+        let mut proposals = ProposalCollector::new();
+        assert_eq!(proposals.add(proposal(vec![1])), Ok(true));
+        // same proposer, same (height, round), different content
+        match proposals.add(proposal(vec![2])) {
+            Err(BftError::MultiProposal(height, round)) => {
+                assert_eq!(height, 1);
+                assert_eq!(round, 0);
+            }
+            other => panic!("expected MultiProposal, got {:?}", other),
+        }
+        // the first, legitimate proposal is still the one served up
+        assert_eq!(
+            proposals.get_proposal(1, 0, &vec![9]),
+            Some(proposal(vec![1]))
+        );
+    }
+}
@@ -0,0 +1,72 @@
+// CITA
+// Copyright 2016-2019 Cryptape Technologies LLC.
+
+// This program is free software: you can redistribute it
+// and/or modify it under the terms of the GNU General Public
+// License as published by the Free Software Foundation,
+// either version 3 of the License, or (at your option) any
+// later version.
+
+// This program is distributed in the hope that it will be
+// useful, but WITHOUT ANY WARRANTY; without even the implied
+// warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+use crate::params::Step;
+
+use crossbeam::crossbeam_channel::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// A timeout that has fired, tagged with the height/round/step it was armed
+/// for so a stale timer (one set before the state machine moved on) can be
+/// told apart from a live one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeoutInfo {
+    /// The height the timeout was armed for.
+    pub height: u64,
+    /// The round the timeout was armed for.
+    pub round: u64,
+    /// The step the timeout was armed for.
+    pub step: Step,
+}
+
+/// Arms per-step timeouts on a dedicated thread. The BFT state machine
+/// sends a `(TimeoutInfo, Duration)` request every time it enters a new
+/// step; `WaitTimer` waits `Duration` and then sends the matching
+/// `TimeoutInfo` back out. Requests aren't cancelled when a later one
+/// arrives -- the state machine tells a stale firing apart by comparing
+/// the returned height/round/step against its current one.
+pub struct WaitTimer {
+    request_receiver: Receiver<(TimeoutInfo, Duration)>,
+    timeout_sender: Sender<TimeoutInfo>,
+}
+
+impl WaitTimer {
+    /// Build a timer that reads arm requests from `request_receiver` and
+    /// reports firings on `timeout_sender`.
+    pub fn new(
+        timeout_sender: Sender<TimeoutInfo>,
+        request_receiver: Receiver<(TimeoutInfo, Duration)>,
+    ) -> Self {
+        WaitTimer {
+            request_receiver,
+            timeout_sender,
+        }
+    }
+
+    /// Start the timer's request loop on a dedicated thread.
+    pub fn start(self) {
+        thread::spawn(move || {
+            while let Ok((info, duration)) = self.request_receiver.recv() {
+                let sender = self.timeout_sender.clone();
+                thread::spawn(move || {
+                    thread::sleep(duration);
+                    let _ = sender.send(info);
+                });
+            }
+        });
+    }
+}
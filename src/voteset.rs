@@ -14,12 +14,73 @@
 
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
-use super::{Address, Target, Vote, VoteType};
+use super::{Address, BftSupport, Evidence, Target, Vote, VoteType};
 use lru_cache::LruCache;
+use rustc_serialize::json::{Json, ToJson};
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::io::prelude::*;
 
+/// A constant-size quorum certificate: the set of authorities who voted for
+/// `proposal`, recorded as a bitfield over the `authority_list` ordering,
+/// plus a single aggregated signature over their votes (e.g. BLS or
+/// threshold). Replaces shipping one full `Vote` per validator.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AggregatedVote {
+    /// Whether this QC aggregates prevotes or precommits.
+    pub vote_type: VoteType,
+    /// The height the votes were cast at.
+    pub height: u64,
+    /// The round the votes were cast at.
+    pub round: u64,
+    /// The proposal the votes are for.
+    pub proposal: Target,
+    /// `voters[i]` is `true` if `authority_list[i]` signed this QC.
+    pub voters: Vec<bool>,
+    /// The aggregated signature over the votes of every authority marked in
+    /// `voters`.
+    pub signature: Vec<u8>,
+}
+
+impl ToJson for AggregatedVote {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.insert("vote type".to_string(), self.vote_type.to_json());
+        d.insert("height".to_string(), self.height.to_json());
+        d.insert("round".to_string(), self.round.to_json());
+        d.insert("proposal".to_string(), self.proposal.to_json());
+        d.insert("voters".to_string(), self.voters.to_json());
+        d.insert("signature".to_string(), self.signature.to_json());
+        Json::Object(d)
+    }
+}
+
+// Outcome of inserting a vote into a `VoteSet`. Kept internal to this
+// module: `VoteCollector::add` has the height/round/vote_type context
+// needed to turn a `Conflict` into a full `Evidence`, so it is the one
+// that hands a `VoteAddResult` back to the caller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SetOutcome {
+    Added,
+    Duplicate,
+    // the sender had already voted for this other proposal
+    Conflict(Target),
+}
+
+/// Outcome of inserting a single vote into a `VoteCollector`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VoteAddResult {
+    /// The vote was new and has been recorded.
+    Added,
+    /// The sender had already cast this exact vote; nothing changed.
+    Duplicate,
+    /// The sender had already voted for a different proposal at the same
+    /// height/round/step. The Byzantine evidence is returned so the caller
+    /// can report the slashable misbehavior upward.
+    Equivocation(Evidence),
+}
+
 #[derive(Debug)]
 pub struct VoteCollector {
     pub votes: LruCache<usize, RoundCollector>,
@@ -34,48 +95,69 @@ impl VoteCollector {
         }
     }
 
-    pub fn add(&mut self, vote: Vote) -> bool {
+    pub fn add(&mut self, vote: Vote) -> VoteAddResult {
+        self.add_weighted(vote, 1)
+    }
+
+    // same as `add`, but counts the vote as `weight` instead of a flat `1`,
+    // so that stake-weighted authority sets reach their +2/3 threshold on
+    // voting power rather than on vote cardinality
+    pub fn add_weighted(&mut self, vote: Vote, weight: u64) -> VoteAddResult {
         let height = vote.height;
         let round = vote.round;
         let vote_type = vote.vote_type;
-        let sender = vote.voter;
-        let vote = vote.proposal;
-
-        if vote_type == VoteType::Prevote {
-            if self.votes.contains_key(&height) {
-                if self
-                    .votes
-                    .get_mut(&height)
-                    .unwrap()
-                    .add(round, vote_type, sender, vote)
-                {
-                    // update prevote count hashmap
-                    let counter = self.prevote_count.entry(round).or_insert(0);
-                    *counter += 1;
-                    true
-                } else {
-                    // if add prevote fail, do not update prevote hashmap
-                    false
-                }
-            } else {
-                let mut round_votes = RoundCollector::new();
-                round_votes.add(round, vote_type, sender, vote);
-                self.votes.insert(height, round_votes);
-                // update prevote count hashmap
-                let counter = self.prevote_count.entry(round).or_insert(0);
-                *counter += 1;
-                true
-            }
-        } else if self.votes.contains_key(&height) {
-            self.votes
-                .get_mut(&height)
-                .unwrap()
-                .add(round, vote_type, sender, vote)
+        // `self.votes`/`self.prevote_count` key on `usize`, while `Vote`'s
+        // height/round are `u64`; cast once here rather than at every call
+        // site, matching what `ProposalCollector::add` already does.
+        let height_key = height as usize;
+        let round_key = round as usize;
+
+        let outcome = if self.votes.contains_key(&height_key) {
+            self.votes.get_mut(&height_key).unwrap().add(
+                round_key,
+                vote_type,
+                vote.voter.clone(),
+                vote.proposal.clone(),
+                weight,
+            )
         } else {
             let mut round_votes = RoundCollector::new();
-            round_votes.add(round, vote_type, sender, vote);
-            self.votes.insert(height, round_votes);
-            true
+            let outcome = round_votes.add(
+                round_key,
+                vote_type,
+                vote.voter.clone(),
+                vote.proposal.clone(),
+                weight,
+            );
+            self.votes.insert(height_key, round_votes);
+            outcome
+        };
+
+        if vote_type == VoteType::Prevote && outcome == SetOutcome::Added {
+            // update prevote count hashmap
+            let counter = self.prevote_count.entry(round_key).or_insert(0);
+            *counter += 1;
+        }
+
+        match outcome {
+            SetOutcome::Added => VoteAddResult::Added,
+            SetOutcome::Duplicate => VoteAddResult::Duplicate,
+            SetOutcome::Conflict(first) => VoteAddResult::Equivocation(Evidence {
+                height,
+                round,
+                vote_type,
+                voter: vote.voter.clone(),
+                first: first.clone(),
+                second: vote.proposal.clone(),
+                first_vote: Vote {
+                    vote_type,
+                    height,
+                    round,
+                    proposal: first,
+                    voter: vote.voter.clone(),
+                },
+                second_vote: vote,
+            }),
         }
     }
 
@@ -96,11 +178,20 @@ impl VoteCollector {
 }
 
 // 1. sender's vote message  2. proposal's hash  3. count
+//
+// `count` and `votes_by_proposal` track the sum of voting power rather than
+// the number of votes, so that an unweighted caller (every vote weighted
+// `1`) and a stake-weighted caller (weights sourced from `Status::authority_weights`)
+// can share the same +2/3 threshold logic.
 #[derive(Clone, Debug)]
 pub struct VoteSet {
     pub votes_by_sender: HashMap<Address, Target>,
-    pub votes_by_proposal: HashMap<Target, usize>,
-    pub count: usize,
+    pub votes_by_proposal: HashMap<Target, u64>,
+    pub count: u64,
+    // second (and later) proposals seen from a sender that already has a
+    // vote recorded in `votes_by_sender`; kept so equivocation evidence can
+    // be reconstructed without re-deriving it from the network
+    pub conflicts: HashMap<Address, Vec<Target>>,
 }
 
 impl VoteSet {
@@ -109,21 +200,81 @@ impl VoteSet {
             votes_by_sender: HashMap::new(),
             votes_by_proposal: HashMap::new(),
             count: 0,
+            conflicts: HashMap::new(),
         }
     }
 
     // just add, not check
     pub fn add(&mut self, sender: Address, vote: Target) -> bool {
-        let mut is_add = false;
-        self.votes_by_sender.entry(sender).or_insert_with(|| {
-            is_add = true;
-            vote.to_owned()
-        });
-        if is_add {
-            self.count += 1;
-            *self.votes_by_proposal.entry(vote).or_insert(0) += 1;
+        self.add_weighted(sender, vote, 1) == SetOutcome::Added
+    }
+
+    // just add, not check, weighting the vote by `weight` instead of `1`
+    fn add_weighted(&mut self, sender: Address, vote: Target, weight: u64) -> SetOutcome {
+        if let Some(existing) = self.votes_by_sender.get(&sender) {
+            return if *existing == vote {
+                SetOutcome::Duplicate
+            } else {
+                let first = existing.clone();
+                self.conflicts
+                    .entry(sender)
+                    .or_insert_with(Vec::new)
+                    .push(vote);
+                SetOutcome::Conflict(first)
+            };
+        }
+        self.votes_by_sender.insert(sender, vote.clone());
+        self.count += weight;
+        *self.votes_by_proposal.entry(vote).or_insert(0) += weight;
+        SetOutcome::Added
+    }
+
+    /// Return the summed voting power of all votes cast for `proposal`, or
+    /// `0` if nobody has voted for it. With no weights supplied this is the
+    /// same as the plain vote count.
+    pub fn weighted_count(&self, proposal: &Target) -> u64 {
+        self.votes_by_proposal.get(proposal).cloned().unwrap_or(0)
+    }
+
+    /// Collapse the votes cast for `proposal` into a constant-size
+    /// `AggregatedVote`: a bitfield of which `authority_list` members
+    /// signed, plus a single aggregated signature produced by `support`'s
+    /// `aggregate_signatures` hook.
+    pub fn into_qc<T: BftSupport>(
+        &self,
+        height: usize,
+        round: usize,
+        vote_type: VoteType,
+        proposal: &Target,
+        authority_list: &[Address],
+        support: &T,
+    ) -> AggregatedVote {
+        let mut voters = Vec::with_capacity(authority_list.len());
+        let mut signing_votes = Vec::new();
+        for address in authority_list {
+            let signed = self
+                .votes_by_sender
+                .get(address)
+                .map_or(false, |voted_for| voted_for == proposal);
+            voters.push(signed);
+            if signed {
+                signing_votes.push(Vote {
+                    vote_type,
+                    height: height as u64,
+                    round: round as u64,
+                    proposal: proposal.clone(),
+                    voter: address.clone(),
+                });
+            }
+        }
+        AggregatedVote {
+            vote_type,
+            height: height as u64,
+            round: round as u64,
+            proposal: proposal.clone(),
+            signature: support.aggregate_signatures(&signing_votes),
+            voters,
         }
-        is_add
     }
 
     pub fn abstract_polc(
@@ -163,23 +314,24 @@ impl RoundCollector {
         }
     }
 
-    pub fn add(
+    fn add(
         &mut self,
         round: usize,
         vote_type: VoteType,
         sender: Address,
         vote: Target,
-    ) -> bool {
+        weight: u64,
+    ) -> SetOutcome {
         if self.round_votes.contains_key(&round) {
             self.round_votes
                 .get_mut(&round)
                 .unwrap()
-                .add(vote_type, sender, vote)
+                .add(vote_type, sender, vote, weight)
         } else {
             let mut step_votes = StepCollector::new();
-            step_votes.add(vote_type, sender, vote);
+            let outcome = step_votes.add(vote_type, sender, vote, weight);
             self.round_votes.insert(round, step_votes);
-            true
+            outcome
         }
     }
 
@@ -203,14 +355,127 @@ impl StepCollector {
         }
     }
 
-    pub fn add(&mut self, vote_type: VoteType, sender: Address, vote: Target) -> bool {
+    fn add(&mut self, vote_type: VoteType, sender: Address, vote: Target, weight: u64) -> SetOutcome {
         self.step_votes
             .entry(vote_type)
             .or_insert_with(VoteSet::new)
-            .add(sender, vote)
+            .add_weighted(sender, vote, weight)
     }
 
     pub fn get_voteset(&self, vote_type: VoteType) -> Option<VoteSet> {
         self.step_votes.get(&vote_type).cloned()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_quorum_needs_stake_not_head_count() {
+        // 3 validators weighted [2, 1, 1]; the first validator alone already
+        // controls half the total weight, not a third of it like a plain
+        // headcount would suggest.
+        let mut votes = VoteSet::new();
+        let proposal: Target = vec![9];
+        assert_eq!(
+            votes.add_weighted(vec![1], proposal.clone(), 2),
+            SetOutcome::Added
+        );
+        assert_eq!(votes.weighted_count(&proposal), 2);
+
+        // a second, lower-weighted validator votes for the same proposal:
+        // the tally sums stake (2 + 1 = 3), not the number of voters (2)
+        assert_eq!(
+            votes.add_weighted(vec![2], proposal.clone(), 1),
+            SetOutcome::Added
+        );
+        assert_eq!(votes.weighted_count(&proposal), 3);
+    }
+
+    struct MockSupport;
+
+    impl BftSupport for MockSupport {
+        fn check_proposal(&self, _proposal: &Target, _height: u64, _round: u64) -> bool {
+            true
+        }
+        fn transmit(&self, _msg: crate::BftMsg) {}
+        fn transmit_to(&self, _target: &Address, _msg: crate::BftMsg) {}
+        fn commit(&self, _commit: crate::Commit) {}
+        fn aggregate_signatures(&self, votes: &[Vote]) -> Vec<u8> {
+            // stand in for a real signature aggregation scheme: just record
+            // how many votes were signed over, which is enough to check
+            // `into_qc` picked the right voters.
+            vec![votes.len() as u8]
+        }
+        fn verify_aggregate(&self, _qc: &AggregatedVote, _authority_list: &[Address]) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn into_qc_sets_the_bitfield_in_authority_list_order() {
+        // authority_list and voting order deliberately differ, so a bug that
+        // indexes `voters` by vote-arrival order instead of `authority_list`
+        // position would show up as a bitfield mismatch here.
+        let authority_list: Vec<Address> = vec![vec![1], vec![2], vec![3]];
+        let proposal: Target = vec![9];
+
+        let mut votes = VoteSet::new();
+        assert_eq!(votes.add(vec![3], proposal.clone()), true);
+        assert_eq!(votes.add(vec![1], proposal.clone()), true);
+
+        let qc = votes.into_qc(1, 0, VoteType::Prevote, &proposal, &authority_list, &MockSupport);
+
+        assert_eq!(qc.voters, vec![true, false, true]);
+        assert_eq!(qc.height, 1);
+        assert_eq!(qc.round, 0);
+        assert_eq!(qc.proposal, proposal);
+        // only the 2 signed votes should have been handed to aggregation
+        assert_eq!(qc.signature, vec![2]);
+    }
+
+    #[test]
+    fn into_qc_omits_votes_for_a_different_proposal() {
+        let authority_list: Vec<Address> = vec![vec![1], vec![2]];
+        let proposal: Target = vec![9];
+        let other_proposal: Target = vec![8];
+
+        let mut votes = VoteSet::new();
+        assert_eq!(votes.add(vec![1], proposal.clone()), true);
+        assert_eq!(votes.add(vec![2], other_proposal), true);
+
+        let qc = votes.into_qc(1, 0, VoteType::Prevote, &proposal, &authority_list, &MockSupport);
+
+        assert_eq!(qc.voters, vec![true, false]);
+        assert_eq!(qc.signature, vec![1]);
+    }
+
+    #[test]
+    fn double_vote_is_reported_as_equivocation() {
+        let mut collector = VoteCollector::new();
+        let first = Vote {
+            vote_type: VoteType::Prevote,
+            height: 1,
+            round: 0,
+            proposal: vec![1],
+            voter: vec![9],
+        };
+        let second = Vote {
+            vote_type: VoteType::Prevote,
+            height: 1,
+            round: 0,
+            proposal: vec![2],
+            voter: vec![9],
+        };
+        assert_eq!(collector.add(first), VoteAddResult::Added);
+        match collector.add(second) {
+            VoteAddResult::Equivocation(evidence) => {
+                assert_eq!(evidence.first, vec![1]);
+                assert_eq!(evidence.second, vec![2]);
+                assert_eq!(evidence.voter, vec![9]);
+            }
+            other => panic!("expected Equivocation, got {:?}", other),
+        }
+    }
+}